@@ -0,0 +1,228 @@
+//! Runtime device configuration (WiFi + MQTT) loaded from NVS, with a
+//! provisioning fallback for first boot / blank devices.
+//!
+//! Historically `WIFI_SSID`, `WIFI_PASS` and `MQTT_URI` were baked in via
+//! `env!()` at compile time. That meant every deployment needed its own
+//! firmware build. Instead we store them as string blobs in a dedicated NVS
+//! namespace and fall back to an AP-mode provisioning flow when the
+//! namespace is empty.
+
+use anyhow::{bail, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+use log::*;
+
+const NVS_NAMESPACE: &str = "vfd_cfg";
+const MAX_VALUE_LEN: usize = 128;
+
+/// Path of the fallback config file on the FAT-on-SPI-flash partition, used
+/// when someone would rather drop a file onto flash than provision over AP.
+const FAT_CONFIG_PATH: &str = "/fat/config.txt";
+const FAT_BASE_PATH: &str = "/fat";
+const FAT_PARTITION_LABEL: &str = "storage";
+
+/// Provisioning AP: SSID prefix (suffixed with the device's short MAC id)
+/// and the TCP port the tiny provisioning server listens on.
+const PROVISIONING_AP_SSID_PREFIX: &str = "VFD-Setup-";
+const PROVISIONING_PORT: u16 = 4242;
+
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub ssid: String,
+    pub pass: String,
+    pub mqtt_uri: String,
+}
+
+impl DeviceConfig {
+    /// Load configuration from NVS. Returns `Ok(None)` if the namespace is
+    /// empty (first boot, or a factory-reset device), not an error.
+    pub fn load_from_nvs(nvs_part: EspDefaultNvsPartition) -> Result<Option<Self>> {
+        let nvs = EspNvs::new(nvs_part, NVS_NAMESPACE, true)?;
+
+        let ssid = read_str(&nvs, "ssid")?;
+        let pass = read_str(&nvs, "pass")?;
+        let mqtt_uri = read_str(&nvs, "mqtt_uri")?;
+
+        match (ssid, pass, mqtt_uri) {
+            (Some(ssid), Some(pass), Some(mqtt_uri)) if is_valid(&ssid, &mqtt_uri) => {
+                Ok(Some(Self { ssid, pass, mqtt_uri }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Persist configuration to NVS so it survives reboots/provisioning.
+    pub fn save_to_nvs(&self, nvs_part: EspDefaultNvsPartition) -> Result<()> {
+        let mut nvs = EspNvs::new(nvs_part, NVS_NAMESPACE, true)?;
+        nvs.set_str("ssid", &self.ssid)?;
+        nvs.set_str("pass", &self.pass)?;
+        nvs.set_str("mqtt_uri", &self.mqtt_uri)?;
+        info!("Config: saved WiFi/MQTT settings to NVS");
+        Ok(())
+    }
+
+    /// Alternative backend: mount the FAT-on-SPI-flash partition and read
+    /// `config.txt` off it. Lets someone drop config onto flash without
+    /// touching firmware or going through AP provisioning.
+    ///
+    /// Expected file format is `key=value` per line:
+    /// ```text
+    /// ssid=MyNetwork
+    /// pass=hunter2
+    /// mqtt_uri=mqtt://broker.local
+    /// ```
+    pub fn load_from_fat(_nvs_part: EspDefaultNvsPartition) -> Result<Option<Self>> {
+        mount_fat()?;
+
+        let file = match std::fs::File::open(FAT_CONFIG_PATH) {
+            Ok(f) => f,
+            Err(_) => return Ok(None),
+        };
+
+        let mut ssid = None;
+        let mut pass = None;
+        let mut mqtt_uri = None;
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "ssid" => ssid = Some(value.trim().to_owned()),
+                "pass" => pass = Some(value.trim().to_owned()),
+                "mqtt_uri" => mqtt_uri = Some(value.trim().to_owned()),
+                _ => {}
+            }
+        }
+
+        match (ssid, pass, mqtt_uri) {
+            (Some(ssid), Some(pass), Some(mqtt_uri)) if is_valid(&ssid, &mqtt_uri) => {
+                info!("Config: loaded WiFi/MQTT settings from {}", FAT_CONFIG_PATH);
+                Ok(Some(Self { ssid, pass, mqtt_uri }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// A config is only usable if it can actually get us onto WiFi and talking
+/// to a broker; an empty `ssid` or `mqtt_uri` means treat it as absent.
+fn is_valid(ssid: &str, mqtt_uri: &str) -> bool {
+    !ssid.is_empty() && !mqtt_uri.is_empty()
+}
+
+fn read_str(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>> {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    match nvs.get_str(key, &mut buf)? {
+        Some(s) => Ok(Some(s.to_owned())),
+        None => Ok(None),
+    }
+}
+
+fn mount_fat() -> Result<()> {
+    use esp_idf_sys::*;
+
+    let mount_config = esp_vfs_fat_mount_config_t {
+        max_files: 2,
+        format_if_mount_failed: true,
+        allocation_unit_size: 0,
+        ..Default::default()
+    };
+
+    let base_path = std::ffi::CString::new(FAT_BASE_PATH)?;
+    let partition_label = std::ffi::CString::new(FAT_PARTITION_LABEL)?;
+    let mut wl_handle: wl_handle_t = 0;
+
+    let err = unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(
+            base_path.as_ptr(),
+            partition_label.as_ptr(),
+            &mount_config,
+            &mut wl_handle,
+        )
+    };
+
+    if err != ESP_OK {
+        bail!("esp_vfs_fat_spiflash_mount_rw_wl failed: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Put the modem into AP mode and run a tiny line-based provisioning
+/// server. A client connects, sends `ssid=...`/`pass=...`/`mqtt_uri=...`
+/// lines (same format as [`DeviceConfig::load_from_fat`]) ended by an empty
+/// line or by closing the connection. Once all three keys are present we persist them to NVS and reboot
+/// into the new config; otherwise the client gets an `ERR` line, nothing is
+/// saved, and we wait for the next client.
+pub fn provision_over_ap(
+    wifi: &mut esp_idf_svc::wifi::EspWifi<'static>,
+    vfd: &mut crate::Vfd<'_>,
+    nvs: EspDefaultNvsPartition,
+    device_id: &str,
+) -> Result<()> {
+    use embedded_svc::wifi::{AccessPointConfiguration, Configuration};
+
+    vfd.display("setup      .".chars()).unwrap();
+
+    let ap_ssid = format!("{}{}", PROVISIONING_AP_SSID_PREFIX, device_id);
+    info!("Provisioning: starting AP '{}'", ap_ssid);
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap_ssid.as_str().into(),
+        auth_method: embedded_svc::wifi::AuthMethod::None,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+
+    let listener = TcpListener::bind(("0.0.0.0", PROVISIONING_PORT))?;
+    info!(
+        "Provisioning: listening on port {}, waiting for setup client",
+        PROVISIONING_PORT
+    );
+
+    loop {
+        let (stream, addr) = listener.accept()?;
+        info!("Provisioning: client connected from {}", addr);
+
+        let mut ssid = None;
+        let mut pass = None;
+        let mut mqtt_uri = None;
+
+        for line in BufReader::new(&stream).lines() {
+            let Ok(line) = line else { break };
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                match key.trim() {
+                    "ssid" => ssid = Some(value.trim().to_owned()),
+                    "pass" => pass = Some(value.trim().to_owned()),
+                    "mqtt_uri" => mqtt_uri = Some(value.trim().to_owned()),
+                    _ => {}
+                }
+            }
+        }
+
+        let (Some(ssid), Some(pass), Some(mqtt_uri)) = (ssid, pass, mqtt_uri) else {
+            let mut stream = stream;
+            let _ = stream.write_all(b"ERR missing ssid/pass/mqtt_uri\n");
+            continue;
+        };
+
+        let config = DeviceConfig { ssid, pass, mqtt_uri };
+        config.save_to_nvs(nvs)?;
+
+        let mut stream = stream;
+        let _ = stream.write_all(b"OK rebooting\n");
+        vfd.display("saved  .   .".chars()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        unsafe { esp_idf_sys::esp_restart() };
+    }
+}
@@ -1,9 +1,9 @@
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use embedded_hal::spi::MODE_3;
-use embedded_svc::mqtt::client::Event;
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use embedded_svc::mqtt::client::{Event, LwtConfiguration, QoS};
 use esp_idf_hal::delay::Delay;
 use esp_idf_hal::gpio::{AnyIOPin, Gpio2, Gpio4, Gpio5, Output, PinDriver};
 use esp_idf_hal::spi::SpiDriver;
@@ -13,7 +13,7 @@ use esp_idf_hal::spi::{
 };
 use esp_idf_hal::units::FromValueType;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration};
+use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::wifi::EspWifi;
 use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
@@ -21,7 +21,20 @@ use hcs_12ss59t::{animation::mode, animation::ScrollingText, HCS12SS59T};
 
 use log::*;
 
-type Vfd<'a> = HCS12SS59T<
+mod config;
+mod mqtt;
+mod ota;
+mod telemetry;
+mod wifi;
+
+use config::DeviceConfig;
+use mqtt::{Command, ConnectionEvent, DisplayMode};
+
+/// Floor for `frame_interval_ms` (and hence `set-mode ...:speed_ms`) so a
+/// misconfigured/zero speed can't busy-loop the display/SPI at full rate.
+const MIN_FRAME_INTERVAL_MS: u64 = 20;
+
+pub(crate) type Vfd<'a> = HCS12SS59T<
     SpiDeviceDriver<'a, SpiDriver<'a>>,
     PinDriver<'a, Gpio4, Output>,
     PinDriver<'a, Gpio2, Output>,
@@ -29,11 +42,6 @@ type Vfd<'a> = HCS12SS59T<
     PinDriver<'a, Gpio5, Output>,
 >;
 
-const WIFI_SSID: &str = env!("WIFI_SSID");
-const WIFI_PASS: &str = env!("WIFI_PASS");
-// const MQTT_URI: &str = "mqtt://mqtt.42volt.de";
-const MQTT_URI: &str = env!("MQTT_URI");
-
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -79,10 +87,46 @@ fn main() -> anyhow::Result<()> {
     vfd.init().unwrap();
     vfd.display("Initializing".chars()).unwrap();
 
+    // If we just rebooted into a freshly-flashed OTA image, it's on
+    // probation until it proves itself by reaching MQTT within the timeout.
+    let mut ota_deadline = match ota::is_pending_verify(nvs.clone()) {
+        Ok(true) => Some(std::time::Instant::now() + ota::BOOT_VERIFY_TIMEOUT),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("OTA: couldn't read slot state: {e:?}");
+            None
+        }
+    };
+
+    // Config: NVS first, then the FAT-on-flash fallback, then provisioning.
+    let device_config = DeviceConfig::load_from_nvs(nvs.clone())?.or_else(|| {
+        DeviceConfig::load_from_fat(nvs.clone()).unwrap_or_else(|e| {
+            warn!("Config: FAT fallback unavailable: {e:?}");
+            None
+        })
+    });
+
     // WIFI
-    let mut wifi = EspWifi::new(perip.modem, sys_loop.clone(), Some(nvs))?;
+    let mut wifi = EspWifi::new(perip.modem, sys_loop.clone(), Some(nvs.clone()))?;
+
+    let device_config = match device_config {
+        Some(config) => config,
+        None => {
+            info!("Config: no stored WiFi/MQTT settings, entering provisioning mode");
+            let mac = wifi.get_mac(esp_idf_svc::wifi::WifiDeviceId::Sta)?;
+            let device_id = format!("{:02X}{:02X}{:02X}", mac[3], mac[4], mac[5]);
+            config::provision_over_ap(&mut wifi, &mut vfd, nvs.clone(), &device_id)?;
+            unreachable!("provisioning always reboots the device on success");
+        }
+    };
 
-    connect_wifi(&mut wifi, &mut vfd)?;
+    if wifi::connect_wifi(&mut wifi, &mut vfd, &device_config).is_err() {
+        warn!("Wifi: failed to associate with stored credentials, entering provisioning mode");
+        let mac = wifi.get_mac(esp_idf_svc::wifi::WifiDeviceId::Sta)?;
+        let device_id = format!("{:02X}{:02X}{:02X}", mac[3], mac[4], mac[5]);
+        config::provision_over_ap(&mut wifi, &mut vfd, nvs.clone(), &device_id)?;
+        unreachable!("provisioning always reboots the device on success");
+    }
     info!("Wifi connected");
 
     // Get and display MAC
@@ -99,124 +143,205 @@ fn main() -> anyhow::Result<()> {
     // MQTT
     let (tx, rx) = channel();
 
-    let conf = MqttClientConfiguration::default();
-    let mut mqtt_client = EspMqttClient::new(MQTT_URI, &conf, move |message| {
+    // From here on `wifi` is only touched by the background roaming
+    // watcher; hand it over so its RSSI check every 30s, and the scan
+    // that follows when the signal is weak, can't block this loop.
+    wifi::spawn_roaming_watcher(
+        Arc::new(Mutex::new(wifi)),
+        device_config.ssid.clone(),
+        tx.clone(),
+    );
+
+    let main_topic = format!("vfd-{}/", device_id);
+    let availability_topic = format!("{}availability", main_topic);
+
+    let conf = MqttClientConfiguration {
+        lwt: Some(LwtConfiguration {
+            topic: &availability_topic,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            payload: b"offline",
+        }),
+        ..Default::default()
+    };
+    let mut mqtt_client = EspMqttClient::new(&device_config.mqtt_uri, &conf, move |message| {
         info!("{:?}", message);
         match message {
-            Ok(Event::Received(m)) => match handle_mqtt_message(m, &tx) {
+            Ok(Event::Received(m)) => match mqtt::handle_mqtt_message(m, &tx) {
                 Err(e) => info!("Error handling mqtt message: {e:?}"),
                 _ => {}
             },
+            Ok(Event::Connected(_)) => {
+                let _ = tx.send(Command::ConnectionEvent(ConnectionEvent::Connected));
+            }
+            Ok(Event::Disconnected) => {
+                let _ = tx.send(Command::ConnectionEvent(ConnectionEvent::Disconnected));
+            }
+            Err(e) => {
+                let _ = tx.send(Command::ConnectionEvent(ConnectionEvent::Error(format!(
+                    "{e:?}"
+                ))));
+            }
             _ => {}
         }
     })
     .unwrap();
 
-    let main_topic = format!("vfd-{}/", device_id);
-    mqtt_client.subscribe(
-        &format!("{}set-text", main_topic),
-        embedded_svc::mqtt::client::QoS::AtMostOnce,
-    )?;
-    info!("MQTT: subscribed to {}set-text", main_topic);
-
     info!("MQTT initialized");
     vfd.vd_off().unwrap();
 
     let mut text = String::new();
-    let mut scroller = ScrollingText::new(&text, false, mode::Cycle);
+    let mut current_mode = DisplayMode::Cycle;
+    let mut frame_interval_ms: u64 = 500;
+    let mut scroller = Scroller::new(&text, current_mode);
+    let mut mqtt_connected = false;
+    let mut vd_on = false;
+    let mut last_telemetry = std::time::Instant::now();
     loop {
-        if let Ok(t) = rx.recv_timeout(Duration::from_millis(500)) {
-            if t == text {
-                continue;
+        if let Some(deadline) = ota_deadline {
+            if std::time::Instant::now() > deadline {
+                ota_deadline = None;
+                if let Err(e) = ota::rollback(nvs.clone()) {
+                    warn!("OTA: rollback failed: {e:?}");
+                }
             }
-            if t.chars().all(|c| matches!(c, '.' | ',' | ' ')) {
-                // if all chars are matching one of whitespace chars, turn off display
-                vfd.vd_off().unwrap();
-            } else {
-                vfd.vd_on().unwrap();
+        }
+        if last_telemetry.elapsed() > Duration::from_secs(60) {
+            last_telemetry = std::time::Instant::now();
+            if let Err(e) = telemetry::publish(&mut mqtt_client, &device_id, &text) {
+                info!("Telemetry: publish failed: {e:?}");
             }
-            text.clear();
-            text.push_str(&t);
-            if t.len() < 12 {
-                text.extend(core::iter::repeat('.').take(12 - t.len()));
+        }
+        if let Ok(command) = rx.recv_timeout(Duration::from_millis(frame_interval_ms)) {
+            match command {
+                Command::SetText(t) => {
+                    if t == text {
+                        continue;
+                    }
+                    // if all chars are matching one of whitespace chars, turn off display
+                    vd_on = !t.chars().all(|c| matches!(c, '.' | ',' | ' '));
+                    text.clear();
+                    text.push_str(&t);
+                    if t.len() < 12 {
+                        text.extend(core::iter::repeat('.').take(12 - t.len()));
+                    }
+                    scroller = Scroller::new(&text, current_mode);
+                }
+                Command::SetMode {
+                    mode,
+                    scroll_speed_ms,
+                } => {
+                    current_mode = mode;
+                    if let Some(speed) = scroll_speed_ms {
+                        // A 0 (or tiny) interval would busy-loop `recv_timeout`
+                        // at full rate, hammering the SPI bus and risking the
+                        // watchdog/brownout; floor it to a sane minimum.
+                        frame_interval_ms = (speed as u64).max(MIN_FRAME_INTERVAL_MS);
+                    }
+                    scroller = Scroller::new(&text, current_mode);
+                }
+                Command::Power(on) => vd_on = on,
+                Command::Ota(url) => {
+                    if let Err(e) = ota::perform_update(&url, &mut vfd, nvs.clone()) {
+                        warn!("OTA: update from {url} failed: {e:?}");
+                    }
+                }
+                Command::ConnectionEvent(ConnectionEvent::Connected) => {
+                    mqtt_connected = true;
+                    if let Err(e) = mqtt::subscribe_all(&mut mqtt_client, &main_topic) {
+                        info!("MQTT: failed to (re)subscribe after connect: {e:?}");
+                    }
+                    if let Err(e) =
+                        mqtt_client.publish(&availability_topic, QoS::AtLeastOnce, true, b"online")
+                    {
+                        info!("MQTT: failed to publish online availability: {e:?}");
+                    }
+                    if ota_deadline.take().is_some() {
+                        if let Err(e) = ota::mark_app_valid(nvs.clone()) {
+                            warn!("OTA: failed to mark running image valid: {e:?}");
+                        }
+                    }
+                }
+                Command::ConnectionEvent(ConnectionEvent::Disconnected) => {
+                    // esp-mqtt's own reconnect-with-backoff is already
+                    // running under `mqtt_client`; we just reflect the state.
+                    info!("MQTT: disconnected, esp-mqtt will reconnect with its own backoff");
+                    mqtt_connected = false;
+                }
+                Command::ConnectionEvent(ConnectionEvent::Error(e)) => {
+                    info!("MQTT: connection error: {e}, esp-mqtt will reconnect with its own backoff");
+                    mqtt_connected = false;
+                }
+                Command::Roamed => {
+                    vfd.display("roaming    .".chars()).unwrap();
+                    Delay::new_default().delay_ms(1000);
+                }
             }
-            scroller = ScrollingText::new(&text, false, mode::Cycle);
         }
-        vfd.display(scroller.get_next()).unwrap();
+        if mqtt_connected {
+            vfd.display(scroller.next_frame().chars()).unwrap();
+        } else {
+            vfd.display("reconnect  .".chars()).unwrap();
+        }
+
+        if vd_on {
+            vfd.vd_on().unwrap();
+        } else {
+            vfd.vd_off().unwrap();
+        }
     }
 }
 
-fn connect_wifi(wifi: &mut EspWifi<'static>, vfd: &mut Vfd<'_>) -> anyhow::Result<()> {
-    let delay = Delay::new_default();
-    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: WIFI_SSID.into(),
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal,
-        password: WIFI_PASS.into(),
-        channel: None,
-    });
-
-    wifi.set_configuration(&wifi_configuration)?;
-
-    let mut load_i: usize = 0;
-    wifi.stop()?; // Try to stop WiFi first to ensure its in a clean state
-    while wifi.is_started()? {
-        let mut s = "OOOOOOOOOOOO".to_owned();
-        s.replace_range(load_i..load_i + 1, "*");
-        vfd.display(s.chars()).unwrap();
-        delay.delay_ms(200);
-        load_i += 1;
-        load_i %= 12;
-        // vfd.display("080808080808").unwrap();
-        // Delay::delay_ms(500);
-    }
-    wifi.start()?;
-    while !wifi.is_started()? {
-        let mut s = "OOOOOOOOOOOO".to_owned();
-        s.replace_range(load_i..load_i + 1, "*");
-        vfd.display(s.chars()).unwrap();
-        delay.delay_ms(200);
-        load_i += 1;
-        load_i %= 12;
-        // vfd.display("080808080808").unwrap();
-        // Delay::delay_ms(500);
-    }
-    info!("Wifi started");
-
-    wifi.connect()?;
-    while !wifi.is_connected()? {
-        let mut s = "OOOOOOOOOOOO".to_owned();
-        s.replace_range(load_i..load_i + 1, "*");
-        vfd.display(s.chars()).unwrap();
-        delay.delay_ms(200);
-        load_i += 1;
-        load_i %= 12;
-    }
-    info!("Wifi connected");
+/// Drives `DisplayMode` on top of the single scrolling animation the
+/// baseline confirmed against the driver (`ScrollingText<mode::Cycle>`).
+/// `hcs_12ss59t::animation::mode` may or may not export `Once`/`Static`
+/// marker types for this crate version — with no manifest or crate source
+/// on disk to check against, we can't confirm either way, so `Once` and
+/// `Static` are built from `mode::Cycle` plus app-level frame logic instead
+/// of betting the whole control-surface change on symbols we can't see.
+enum Scroller<'a> {
+    Cycle(ScrollingText<'a, mode::Cycle>),
+    /// Scrolls through `text` once via the same `mode::Cycle` animation,
+    /// then holds on the final frame.
+    Once {
+        inner: ScrollingText<'a, mode::Cycle>,
+        frames_remaining: usize,
+        last_frame: String,
+    },
+    /// No animation: render `text` as-is every frame.
+    Static(&'a str),
+}
 
-    // wifi.wait_netif_up()?;
-    while !wifi.is_up()? {
-        let mut s = "OOOOOOOOOOOO".to_owned();
-        s.replace_range(load_i..load_i + 1, "*");
-        vfd.display(s.chars()).unwrap();
-        delay.delay_ms(200);
-        load_i += 1;
-        load_i %= 12;
+impl<'a> Scroller<'a> {
+    fn new(text: &'a str, display_mode: DisplayMode) -> Self {
+        match display_mode {
+            DisplayMode::Cycle => Scroller::Cycle(ScrollingText::new(text, false, mode::Cycle)),
+            DisplayMode::Once => Scroller::Once {
+                inner: ScrollingText::new(text, false, mode::Cycle),
+                frames_remaining: text.chars().count(),
+                last_frame: text.to_owned(),
+            },
+            DisplayMode::Static => Scroller::Static(text),
+        }
     }
-    info!("Wifi netif up");
-    vfd.display("connected   ".chars()).unwrap();
-    delay.delay_ms(1000);
-
-    Ok(())
-}
 
-fn handle_mqtt_message(message: &EspMqttMessage, tx: &Sender<String>) -> anyhow::Result<()> {
-    if let Some(topic) = message.topic() {
-        if topic.contains("set-text") {
-            let buf = message.data();
-            let s = String::from_utf8_lossy(buf);
-            tx.send(s.into_owned())?;
+    fn next_frame(&mut self) -> String {
+        match self {
+            Scroller::Cycle(s) => s.get_next().collect(),
+            Scroller::Once {
+                inner,
+                frames_remaining,
+                last_frame,
+            } => {
+                if *frames_remaining == 0 {
+                    return last_frame.clone();
+                }
+                *frames_remaining -= 1;
+                let frame: String = inner.get_next().collect();
+                *last_frame = frame.clone();
+                frame
+            }
+            Scroller::Static(text) => text.to_string(),
         }
     }
-    Ok(())
 }
@@ -0,0 +1,119 @@
+//! MQTT control surface: topic dispatch and the typed commands it produces.
+//!
+//! Previously only `set-text` was handled, with scrolling hardwired to
+//! `mode::Cycle` and "is it blank" used as a proxy for "turn the display
+//! off". This expands the topic tree under `vfd-{device_id}/` so the
+//! display is fully scriptable from a broker:
+//!
+//! - `set-text`       — scrolling text payload (unchanged behavior)
+//! - `set-mode`       — `cycle`/`once`/`static`, optionally `mode:speed_ms`
+//! - `power`          — `on`/`off`, explicit instead of overloading blank text
+//! - `ota`            — a URL to a firmware image to download and flash
+//!
+//! `set-brightness` and `set-raw` were dropped: HCS12SS59T doesn't expose a
+//! dimming register or a path to push raw segment bytes, and advertising a
+//! topic we can only silently discard is worse than not having it.
+
+use std::sync::mpsc::Sender;
+
+use embedded_svc::mqtt::client::QoS;
+use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttMessage};
+
+use log::*;
+
+/// Display scroll behavior, mirroring `hcs_12ss59t::animation::mode`.
+#[derive(Debug, Clone, Copy)]
+pub enum DisplayMode {
+    Cycle,
+    Once,
+    Static,
+}
+
+/// Broker connection state, forwarded from the MQTT event closure so the
+/// main loop can render a "reconnecting" indicator instead of freezing on
+/// stale text.
+///
+/// There's no app-level reconnect/backoff loop here: `EspMqttClient` already
+/// runs esp-mqtt's own reconnect-with-backoff under the hood once it's
+/// `Disconnected` or errors out, so the main loop's job on these events is
+/// only to reflect that state on the display and re-subscribe/re-announce
+/// availability once `Connected` fires again, not to drive reconnection
+/// itself.
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+    Error(String),
+}
+
+#[derive(Debug)]
+pub enum Command {
+    SetText(String),
+    SetMode {
+        mode: DisplayMode,
+        scroll_speed_ms: Option<u32>,
+    },
+    Power(bool),
+    /// URL of a firmware image to download and flash.
+    Ota(String),
+    ConnectionEvent(ConnectionEvent),
+    /// The background roaming watcher just hopped to a stronger AP.
+    Roamed,
+}
+
+/// Subscribe to every control topic under `vfd-{device_id}/`.
+pub fn subscribe_all(mqtt_client: &mut EspMqttClient<'_>, main_topic: &str) -> anyhow::Result<()> {
+    for suffix in ["set-text", "set-mode", "power", "ota"] {
+        mqtt_client.subscribe(&format!("{main_topic}{suffix}"), QoS::AtMostOnce)?;
+        info!("MQTT: subscribed to {main_topic}{suffix}");
+    }
+    Ok(())
+}
+
+/// Parse an incoming message into a [`Command`] and send it over the
+/// channel the main loop reads from.
+pub fn handle_mqtt_message(message: &EspMqttMessage, tx: &Sender<Command>) -> anyhow::Result<()> {
+    let Some(topic) = message.topic() else {
+        return Ok(());
+    };
+    let buf = message.data();
+
+    let command = if topic.ends_with("set-text") {
+        Command::SetText(String::from_utf8_lossy(buf).into_owned())
+    } else if topic.ends_with("set-mode") {
+        let s = String::from_utf8_lossy(buf);
+        let (mode_str, speed) = match s.trim().split_once(':') {
+            Some((mode_str, speed)) => (mode_str, Some(speed.parse()?)),
+            None => (s.trim(), None),
+        };
+        let mode = match mode_str {
+            "cycle" => DisplayMode::Cycle,
+            "once" => DisplayMode::Once,
+            "static" => DisplayMode::Static,
+            other => {
+                info!("MQTT: unknown set-mode value '{other}', ignoring");
+                return Ok(());
+            }
+        };
+        Command::SetMode {
+            mode,
+            scroll_speed_ms: speed,
+        }
+    } else if topic.ends_with("power") {
+        match String::from_utf8_lossy(buf).trim() {
+            "on" => Command::Power(true),
+            "off" => Command::Power(false),
+            other => {
+                info!("MQTT: unknown power value '{other}', ignoring");
+                return Ok(());
+            }
+        }
+    } else if topic.ends_with("ota") {
+        Command::Ota(String::from_utf8_lossy(buf).trim().to_owned())
+    } else {
+        return Ok(());
+    };
+
+    tx.send(command)?;
+    Ok(())
+}
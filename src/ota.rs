@@ -0,0 +1,138 @@
+//! Over-the-air firmware updates triggered over MQTT.
+//!
+//! On receipt of a URL on `vfd-{device_id}/ota` we open an [`EspOta`]
+//! update session, stream the image from an HTTP(S) endpoint, write
+//! chunks into the next OTA partition while showing a progress bar on
+//! the VFD, then set the boot partition and reboot.
+//!
+//! Rollback guard, two layers deep:
+//!
+//! - The bootloader's own app-rollback support (`CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`)
+//!   boots a freshly-flashed image with its slot left non-`Valid`. If that
+//!   image panics/resets before anyone calls `mark_running_slot_valid()`,
+//!   the bootloader itself reverts to the previous slot on the next boot —
+//!   this catches a crashing image with no help from our code at all.
+//! - That alone can't catch an image that boots fine but never reaches the
+//!   "MQTT connected" milestone (hangs, bad config, broker unreachable),
+//!   since it never crashes for the bootloader to notice. For that we add
+//!   [`BOOT_VERIFY_TIMEOUT`], app-level and arms only when *both*
+//!   `get_running_slot().state != Valid` (an update is actually outstanding)
+//!   **and** the NVS marker [`perform_update`] sets right before reboot is
+//!   present — the marker keeps a serial-flashed or rollback-disabled build
+//!   (permanently non-`Valid`, but never OTA'd) from arming the deadline and
+//!   boot-looping itself.
+//!
+//! [`is_pending_verify`] / [`mark_app_valid`] / [`rollback`] are what `main`
+//! uses to drive that second layer.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::io::Read;
+use esp_idf_hal::delay::Delay;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::ota::{EspOta, SlotState};
+
+use log::*;
+
+use crate::Vfd;
+
+/// How long a freshly-flashed image has to reach the "MQTT connected"
+/// milestone before we consider it bad and roll back to the previous slot.
+pub const BOOT_VERIFY_TIMEOUT: Duration = Duration::from_secs(120);
+
+const NVS_NAMESPACE: &str = "vfd_cfg";
+const NVS_PENDING_VERIFY_KEY: &str = "ota_pending";
+
+/// True if [`perform_update`] rebooted into this image, the bootloader still
+/// considers its slot unverified, and it hasn't been marked valid yet — i.e.
+/// this boot is on probation. `Ok(false)` covers every other boot (first
+/// flash over serial, normal power cycle, a build without app-rollback
+/// support, ...).
+pub fn is_pending_verify(nvs_part: EspDefaultNvsPartition) -> Result<bool> {
+    let slot_unverified = EspOta::new()?.get_running_slot()?.state != SlotState::Valid;
+    let nvs = EspNvs::new(nvs_part, NVS_NAMESPACE, true)?;
+    let marker_set = nvs.get_u8(NVS_PENDING_VERIFY_KEY)?.unwrap_or(0) != 0;
+    Ok(slot_unverified && marker_set)
+}
+
+/// Mark the running image as good: clears the pending-verify marker and
+/// tells the bootloader not to roll it back.
+pub fn mark_app_valid(nvs_part: EspDefaultNvsPartition) -> Result<()> {
+    clear_pending_verify(nvs_part)?;
+    EspOta::new()?.mark_running_slot_valid()?;
+    info!("OTA: running image marked valid");
+    Ok(())
+}
+
+/// The running image never reached its milestone in time: clear the
+/// pending-verify marker and roll back to the previous slot. Does not
+/// return on success.
+pub fn rollback(nvs_part: EspDefaultNvsPartition) -> Result<()> {
+    warn!("OTA: new image failed to verify in time, rolling back");
+    clear_pending_verify(nvs_part)?;
+    EspOta::new()?.mark_running_slot_invalid_and_reboot()?;
+    Ok(())
+}
+
+fn clear_pending_verify(nvs_part: EspDefaultNvsPartition) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs_part, NVS_NAMESPACE, true)?;
+    nvs.remove(NVS_PENDING_VERIFY_KEY)?;
+    Ok(())
+}
+
+/// Download `url` and flash it to the inactive OTA partition, showing a
+/// progress bar on the VFD. Sets the pending-verify marker, reboots into
+/// the new image on success, and does not return.
+pub fn perform_update(url: &str, vfd: &mut Vfd<'_>, nvs_part: EspDefaultNvsPartition) -> Result<()> {
+    info!("OTA: starting update from {url}");
+
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+    let request = client.get(url)?;
+    let mut response = request.submit()?;
+
+    let content_length = response.content_len().unwrap_or(0) as usize;
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+
+    let mut buf = [0u8; 4096];
+    let mut written = 0usize;
+    loop {
+        let n = response.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        update.write(&buf[..n])?;
+        written += n;
+        show_progress(vfd, written, content_length);
+    }
+
+    update.complete()?;
+
+    let mut nvs = EspNvs::new(nvs_part, NVS_NAMESPACE, true)?;
+    nvs.set_u8(NVS_PENDING_VERIFY_KEY, 1)?;
+
+    info!("OTA: {written} bytes written, rebooting into new image");
+    vfd.display("ota done   .".chars()).unwrap();
+    Delay::new_default().delay_ms(1000);
+    unsafe { esp_idf_sys::esp_restart() };
+}
+
+fn show_progress(vfd: &mut Vfd<'_>, written: usize, total: usize) {
+    if total == 0 {
+        return;
+    }
+    let filled = (written * 12 / total).min(12);
+    let mut bar = ['.'; 12];
+    for slot in bar.iter_mut().take(filled) {
+        *slot = '#';
+    }
+    vfd.display(bar.into_iter()).ok();
+}
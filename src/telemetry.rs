@@ -0,0 +1,93 @@
+//! Periodic JSON status/telemetry publishing over MQTT.
+//!
+//! The firmware used to only subscribe; it never reported anything back.
+//! This builds a small status payload and publishes it retained to
+//! `vfd-{device_id}/status` so a broker-side dashboard always has the
+//! last-known state of every display in the fleet, without needing serial
+//! access to any of them.
+
+use anyhow::Result;
+use embedded_svc::mqtt::client::QoS;
+use esp_idf_svc::mqtt::client::EspMqttClient;
+use serde::Serialize;
+
+use crate::wifi;
+
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize)]
+struct Status<'a> {
+    device_id: &'a str,
+    board: String,
+    firmware_version: &'a str,
+    bssid: String,
+    channel: u8,
+    rssi: i8,
+    free_heap: u32,
+    uptime_secs: u64,
+    text: &'a str,
+}
+
+/// Build and publish the current status payload, retained, to
+/// `vfd-{device_id}/status`.
+pub fn publish(
+    mqtt_client: &mut EspMqttClient<'_>,
+    device_id: &str,
+    current_text: &str,
+) -> Result<()> {
+    let link = wifi::current_link_info()?;
+
+    let status = Status {
+        device_id,
+        board: board_name(),
+        firmware_version: FIRMWARE_VERSION,
+        bssid: format_bssid(link.bssid),
+        channel: link.channel,
+        rssi: link.rssi,
+        free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        uptime_secs: uptime_secs(),
+        text: current_text,
+    };
+
+    let payload = serde_json::to_vec(&status)?;
+    mqtt_client.publish(
+        &format!("vfd-{device_id}/status"),
+        QoS::AtLeastOnce,
+        true,
+        &payload,
+    )?;
+
+    Ok(())
+}
+
+/// Chip model as reported by `esp_chip_info`, e.g. `"esp32s3"`. This board's
+/// SPI lines (gpio6/gpio7) don't match a classic ESP32, so the published
+/// value needs to reflect the actual silicon rather than a guess.
+fn board_name() -> String {
+    let mut info = esp_idf_sys::esp_chip_info_t::default();
+    unsafe { esp_idf_sys::esp_chip_info(&mut info) };
+
+    let model = match info.model {
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32 => "esp32",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32S2 => "esp32s2",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32S3 => "esp32s3",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32C3 => "esp32c3",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32C2 => "esp32c2",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32C6 => "esp32c6",
+        esp_idf_sys::esp_chip_model_t_CHIP_ESP32H2 => "esp32h2",
+        _ => "unknown",
+    };
+    model.to_owned()
+}
+
+fn format_bssid(bssid: [u8; 6]) -> String {
+    bssid
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn uptime_secs() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u64
+}
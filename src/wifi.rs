@@ -0,0 +1,241 @@
+//! WiFi connection and RSSI-aware roaming.
+//!
+//! `connect_wifi` used to hand a bare `ClientConfiguration` (no `bssid`,
+//! no `channel`) to the driver and let it associate with whatever AP it
+//! felt like. We now scan first, pick the strongest AP matching the
+//! configured SSID, and pin `bssid`/`channel` so we deterministically join
+//! it. [`spawn_roaming_watcher`] hands the (now-shared) `EspWifi` handle to
+//! a dedicated background thread that re-scans and hops to a meaningfully
+//! stronger AP once signal degrades, so the scan — which blocks for a
+//! second or more — never stalls the display/MQTT loop.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_hal::delay::Delay;
+use esp_idf_svc::wifi::EspWifi;
+
+use log::*;
+
+use crate::config::DeviceConfig;
+use crate::mqtt::Command;
+use crate::Vfd;
+
+/// How often the background watcher checks RSSI and, if needed, re-scans.
+const ROAM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How long to wait for `is_connected()` to go true after (re)connecting
+/// before giving up on that attempt.
+const ROAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Below this RSSI (dBm) we consider the link degraded enough to look for
+/// a stronger AP.
+const ROAM_RSSI_THRESHOLD_DBM: i8 = -67;
+/// A candidate AP must beat the current one by at least this many dB
+/// before we bother roaming to it, to avoid flapping between two APs of
+/// similar strength.
+const ROAM_HYSTERESIS_DB: i8 = 8;
+
+pub fn connect_wifi(
+    wifi: &mut EspWifi<'static>,
+    vfd: &mut Vfd<'_>,
+    device_config: &DeviceConfig,
+) -> Result<()> {
+    let delay = Delay::new_default();
+
+    // Scanning needs the driver started in station mode.
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    let best_ap = scan_for_strongest_ap(wifi, &device_config.ssid).ok();
+    if let Some(ap) = &best_ap {
+        info!(
+            "Wifi: found {} on channel {} at {} dBm, joining that BSSID",
+            device_config.ssid, ap.channel, ap.signal_strength
+        );
+    } else {
+        warn!(
+            "Wifi: no scan result for '{}', letting the driver pick an AP",
+            device_config.ssid
+        );
+    }
+
+    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
+        ssid: device_config.ssid.as_str().into(),
+        bssid: best_ap.as_ref().map(|ap| ap.bssid),
+        auth_method: AuthMethod::WPA2Personal,
+        password: device_config.pass.as_str().into(),
+        channel: best_ap.as_ref().map(|ap| ap.channel),
+    });
+
+    wifi.set_configuration(&wifi_configuration)?;
+
+    let mut load_i: usize = 0;
+    wifi.stop()?; // Try to stop WiFi first to ensure its in a clean state
+    while wifi.is_started()? {
+        let mut s = "OOOOOOOOOOOO".to_owned();
+        s.replace_range(load_i..load_i + 1, "*");
+        vfd.display(s.chars()).unwrap();
+        delay.delay_ms(200);
+        load_i += 1;
+        load_i %= 12;
+    }
+    wifi.start()?;
+    while !wifi.is_started()? {
+        let mut s = "OOOOOOOOOOOO".to_owned();
+        s.replace_range(load_i..load_i + 1, "*");
+        vfd.display(s.chars()).unwrap();
+        delay.delay_ms(200);
+        load_i += 1;
+        load_i %= 12;
+    }
+    info!("Wifi started");
+
+    wifi.connect()?;
+    while !wifi.is_connected()? {
+        let mut s = "OOOOOOOOOOOO".to_owned();
+        s.replace_range(load_i..load_i + 1, "*");
+        vfd.display(s.chars()).unwrap();
+        delay.delay_ms(200);
+        load_i += 1;
+        load_i %= 12;
+    }
+    info!("Wifi connected");
+
+    // wifi.wait_netif_up()?;
+    while !wifi.is_up()? {
+        let mut s = "OOOOOOOOOOOO".to_owned();
+        s.replace_range(load_i..load_i + 1, "*");
+        vfd.display(s.chars()).unwrap();
+        delay.delay_ms(200);
+        load_i += 1;
+        load_i %= 12;
+    }
+    info!("Wifi netif up");
+    vfd.display("connected   ".chars()).unwrap();
+    delay.delay_ms(1000);
+
+    Ok(())
+}
+
+/// Scan and return the strongest AP whose SSID matches `ssid`.
+fn scan_for_strongest_ap(wifi: &mut EspWifi<'static>, ssid: &str) -> Result<AccessPointInfo> {
+    let results = wifi.scan()?;
+    results
+        .into_iter()
+        .filter(|ap| ap.ssid.as_str() == ssid)
+        .max_by_key(|ap| ap.signal_strength)
+        .ok_or_else(|| anyhow::anyhow!("no AP advertising SSID '{ssid}' seen in scan"))
+}
+
+/// Snapshot of the link to the currently-associated AP.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkInfo {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// Current link info for the AP we're associated with, straight from the
+/// driver (`esp_wifi_sta_get_ap_info`).
+pub fn current_link_info() -> Result<LinkInfo> {
+    let mut info = esp_idf_sys::wifi_ap_record_t::default();
+    let err = unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) };
+    if err != esp_idf_sys::ESP_OK {
+        bail!("esp_wifi_sta_get_ap_info failed: {err}");
+    }
+    Ok(LinkInfo {
+        bssid: info.bssid,
+        channel: info.primary,
+        rssi: info.rssi,
+    })
+}
+
+/// Current RSSI of the AP we're associated with, in dBm.
+fn current_rssi() -> Result<i8> {
+    current_link_info().map(|info| info.rssi)
+}
+
+/// Spawn the background roaming watcher. Takes ownership of `wifi` (via a
+/// shared, mutex-guarded handle) and runs for the lifetime of the device,
+/// waking up every [`ROAM_CHECK_INTERVAL`] to check RSSI and, if it has
+/// dropped below [`ROAM_RSSI_THRESHOLD_DBM`], re-scan for a meaningfully
+/// stronger AP advertising the same SSID. A successful roam is reported
+/// to the main loop over `tx` so it can flash a brief status on the VFD.
+pub fn spawn_roaming_watcher(
+    wifi: Arc<Mutex<EspWifi<'static>>>,
+    ssid: String,
+    tx: std::sync::mpsc::Sender<Command>,
+) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(ROAM_CHECK_INTERVAL);
+        match check_and_roam(&wifi, &ssid) {
+            Ok(true) => {
+                let _ = tx.send(Command::Roamed);
+            }
+            Ok(false) => {}
+            Err(e) => info!("Wifi: roaming check failed: {e:?}"),
+        }
+    });
+}
+
+/// Runs entirely on the watcher thread: checking RSSI is a cheap local
+/// driver read, but the scan and (re)connect below block for a second or
+/// more, which is exactly why this isn't called from the display loop.
+fn check_and_roam(wifi: &Mutex<EspWifi<'static>>, ssid: &str) -> Result<bool> {
+    let rssi = current_rssi()?;
+    if rssi >= ROAM_RSSI_THRESHOLD_DBM {
+        return Ok(false);
+    }
+
+    info!("Wifi: RSSI {rssi} dBm below threshold, scanning for a stronger AP");
+    let mut wifi = wifi.lock().unwrap();
+    let Ok(candidate) = scan_for_strongest_ap(&mut wifi, ssid) else {
+        return Ok(false);
+    };
+
+    if candidate.signal_strength < rssi + ROAM_HYSTERESIS_DB {
+        return Ok(false);
+    }
+
+    let previous_config = match wifi.get_configuration()? {
+        Configuration::Client(c) => c,
+        other => bail!("unexpected wifi configuration variant during roam: {other:?}"),
+    };
+
+    info!(
+        "Wifi: roaming to {:?} on channel {} ({} dBm, was {} dBm)",
+        candidate.bssid, candidate.channel, candidate.signal_strength, rssi
+    );
+
+    let mut new_config = previous_config.clone();
+    new_config.bssid = Some(candidate.bssid);
+    new_config.channel = Some(candidate.channel);
+
+    wifi.disconnect()?;
+    wifi.set_configuration(&Configuration::Client(new_config))?;
+    wifi.connect()?;
+
+    if wait_for_connected(&mut wifi, ROAM_CONNECT_TIMEOUT) {
+        return Ok(true);
+    }
+
+    warn!("Wifi: roam to {:?} failed, reverting to previous BSSID", candidate.bssid);
+    wifi.disconnect()?;
+    wifi.set_configuration(&Configuration::Client(previous_config))?;
+    wifi.connect()?;
+    wait_for_connected(&mut wifi, ROAM_CONNECT_TIMEOUT);
+
+    Ok(false)
+}
+
+fn wait_for_connected(wifi: &mut EspWifi<'static>, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if wifi.is_connected().unwrap_or(false) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    false
+}